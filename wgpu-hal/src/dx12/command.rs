@@ -0,0 +1,574 @@
+use super::{
+    native, BindGroup, Buffer, BufferViewKind, CommandEncoder, HResult as _, PassKind,
+    PipelineLayout, QuerySet, TableTypes,
+};
+use std::{
+    collections::HashMap,
+    mem,
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use winapi::{um::d3d12, Interface as _};
+
+/// Sentinel subresource index meaning "every subresource of this resource",
+/// matching `D3D12_RESOURCE_BARRIER_ALL_SUBRESOURCES`.
+const ALL_SUBRESOURCES: u32 = d3d12::D3D12_RESOURCE_BARRIER_ALL_SUBRESOURCES;
+
+/// Tracks the last known `D3D12_RESOURCE_STATES` of each `(resource, subresource)`
+/// recorded against a command encoder, so that state transitions can be emitted
+/// lazily and coalesced into as few `ResourceBarrier` calls as possible.
+///
+/// This map is cleared at the start of every command list recording (see
+/// `CommandEncoder::begin_encoding`), so on its own it can't tell a resource
+/// that's genuinely never been touched from one that was transitioned by an
+/// *earlier* list and is simply unknown to this fresh tracker. Every lookup
+/// therefore falls back to the resource's own `Buffer::state`/`Texture::state`
+/// — the actual last state it was left in — instead of treating "not in this
+/// tracker" as "already at the state we want, no barrier needed".
+#[derive(Default)]
+pub(super) struct BarrierTracker {
+    states: HashMap<(native::Resource, u32), d3d12::D3D12_RESOURCE_STATES>,
+}
+
+impl BarrierTracker {
+    fn current_state(
+        &self,
+        resource: native::Resource,
+        subresource: u32,
+        resource_state: &AtomicU32,
+    ) -> d3d12::D3D12_RESOURCE_STATES {
+        self.states
+            .get(&(resource, subresource))
+            .or_else(|| self.states.get(&(resource, ALL_SUBRESOURCES)))
+            .copied()
+            .unwrap_or_else(|| resource_state.load(Ordering::Relaxed) as d3d12::D3D12_RESOURCE_STATES)
+    }
+
+    /// Record that `resource` (or all of its subresources, if `subresource` is
+    /// `ALL_SUBRESOURCES`) is moving to `new_state`, pushing a barrier into
+    /// `barriers` if the transition actually needs one, and updating
+    /// `resource_state` (`Buffer::state`/`Texture::state`) to match so a later
+    /// command list recording still knows where this left off.
+    ///
+    /// A same-state `UNORDERED_ACCESS` -> `UNORDERED_ACCESS` transition emits a
+    /// UAV barrier instead of a (no-op) transition barrier, since that's the only
+    /// way D3D12 lets us express a storage read-after-write/write-after-write hazard.
+    pub(super) fn transition(
+        &mut self,
+        barriers: &mut Vec<d3d12::D3D12_RESOURCE_BARRIER>,
+        resource: native::Resource,
+        resource_state: &AtomicU32,
+        subresource: u32,
+        new_state: d3d12::D3D12_RESOURCE_STATES,
+    ) {
+        if subresource == ALL_SUBRESOURCES {
+            self.transition_all(barriers, resource, resource_state, new_state);
+            return;
+        }
+
+        let old_state = self.current_state(resource, subresource, resource_state);
+        if old_state == new_state {
+            if new_state == d3d12::D3D12_RESOURCE_STATE_UNORDERED_ACCESS {
+                barriers.push(uav_barrier(resource));
+            }
+        } else {
+            barriers.push(transition_barrier(resource, subresource, old_state, new_state));
+        }
+        self.states.insert((resource, subresource), new_state);
+        resource_state.store(new_state as u32, Ordering::Relaxed);
+    }
+
+    /// Transition every subresource of `resource` to `new_state` at once.
+    ///
+    /// Every individually-tracked subresource entry for `resource` is
+    /// consulted here, not just a previous bulk (`ALL_SUBRESOURCES`) entry —
+    /// otherwise a resource whose subresources were transitioned one at a
+    /// time would look untracked to a later bulk transition, and the barrier
+    /// it actually needs would be dropped. Once resolved, those per-subresource
+    /// entries are replaced by a single `ALL_SUBRESOURCES` entry so they can't
+    /// shadow a future lookup with now-stale state.
+    fn transition_all(
+        &mut self,
+        barriers: &mut Vec<d3d12::D3D12_RESOURCE_BARRIER>,
+        resource: native::Resource,
+        resource_state: &AtomicU32,
+        new_state: d3d12::D3D12_RESOURCE_STATES,
+    ) {
+        let mut known: Vec<(u32, d3d12::D3D12_RESOURCE_STATES)> = self
+            .states
+            .iter()
+            .filter(|&(&(res, _), _)| res == resource)
+            .map(|(&(_, sub), &state)| (sub, state))
+            .collect();
+
+        if known.is_empty() {
+            // not seen by this (possibly freshly-cleared) tracker yet; fall back
+            // to the resource's own last-known state rather than assuming none
+            // of its subresources need a barrier
+            let old_state = resource_state.load(Ordering::Relaxed) as d3d12::D3D12_RESOURCE_STATES;
+            if old_state == new_state {
+                if new_state == d3d12::D3D12_RESOURCE_STATE_UNORDERED_ACCESS {
+                    barriers.push(uav_barrier(resource));
+                }
+            } else {
+                barriers.push(transition_barrier(resource, ALL_SUBRESOURCES, old_state, new_state));
+            }
+        } else if known.iter().all(|&(_, state)| state == new_state) {
+            if new_state == d3d12::D3D12_RESOURCE_STATE_UNORDERED_ACCESS {
+                barriers.push(uav_barrier(resource));
+            }
+        } else if let [(_, old_state)] = known.as_slice() {
+            barriers.push(transition_barrier(
+                resource,
+                ALL_SUBRESOURCES,
+                *old_state,
+                new_state,
+            ));
+        } else {
+            // subresources disagree on their current state, so a single
+            // ALL_SUBRESOURCES barrier can't express the transition; emit one
+            // per subresource instead (coalesced together by the caller).
+            //
+            // The ALL_SUBRESOURCES sentinel itself is excluded here: it isn't a
+            // literal subresource, and since we don't know this resource's total
+            // subresource count, there's no way to also enumerate "every other
+            // subresource still implicitly at the sentinel's old bulk state."
+            // Combining a bulk barrier with explicit per-subresource barriers
+            // would therefore risk claiming an individually-tracked subresource
+            // is transitioning from the bulk's old state, contradicting the
+            // explicit barrier already emitted for it in the same batch.
+            //
+            // TODO: because the excluded sentinel's subresources are never
+            // enumerated, no barrier is emitted for them here at all, yet the
+            // `states`/`resource_state` update below still records the whole
+            // resource as having reached `new_state`. Subresources that were
+            // only implicitly covered by the old bulk entry are therefore
+            // tracked as further along than they actually are on the GPU,
+            // which can produce a wrong `StateBefore` on their next
+            // transition. Fixing this for real requires tracking each
+            // resource's total subresource count so those subresources can be
+            // enumerated and barriered explicitly instead of just assumed.
+            known.retain(|&(sub, _)| sub != ALL_SUBRESOURCES);
+            known.sort_by_key(|&(sub, _)| sub);
+            for (sub, old_state) in known {
+                if old_state != new_state {
+                    barriers.push(transition_barrier(resource, sub, old_state, new_state));
+                } else if new_state == d3d12::D3D12_RESOURCE_STATE_UNORDERED_ACCESS {
+                    barriers.push(uav_barrier(resource));
+                }
+            }
+        }
+
+        self.states.retain(|&(res, _), _| res != resource);
+        self.states.insert((resource, ALL_SUBRESOURCES), new_state);
+        resource_state.store(new_state as u32, Ordering::Relaxed);
+    }
+
+    /// Drop all recorded state, e.g. once an encoder is reset for reuse.
+    pub(super) fn clear(&mut self) {
+        self.states.clear();
+    }
+}
+
+fn transition_barrier(
+    resource: native::Resource,
+    subresource: u32,
+    state_before: d3d12::D3D12_RESOURCE_STATES,
+    state_after: d3d12::D3D12_RESOURCE_STATES,
+) -> d3d12::D3D12_RESOURCE_BARRIER {
+    let mut barrier: d3d12::D3D12_RESOURCE_BARRIER = unsafe { mem::zeroed() };
+    barrier.Type = d3d12::D3D12_RESOURCE_BARRIER_TYPE_TRANSITION;
+    barrier.Flags = d3d12::D3D12_RESOURCE_BARRIER_FLAG_NONE;
+    unsafe {
+        *barrier.u.Transition_mut() = d3d12::D3D12_RESOURCE_TRANSITION_BARRIER {
+            pResource: resource.as_mut_ptr(),
+            Subresource: subresource,
+            StateBefore: state_before,
+            StateAfter: state_after,
+        };
+    }
+    barrier
+}
+
+fn uav_barrier(resource: native::Resource) -> d3d12::D3D12_RESOURCE_BARRIER {
+    let mut barrier: d3d12::D3D12_RESOURCE_BARRIER = unsafe { mem::zeroed() };
+    barrier.Type = d3d12::D3D12_RESOURCE_BARRIER_TYPE_UAV;
+    barrier.Flags = d3d12::D3D12_RESOURCE_BARRIER_FLAG_NONE;
+    unsafe {
+        *barrier.u.UAV_mut() = d3d12::D3D12_RESOURCE_UAV_BARRIER {
+            pResource: resource.as_mut_ptr(),
+        };
+    }
+    barrier
+}
+
+impl CommandEncoder {
+    /// Record that `resource`'s `subresource` is transitioning to `new_state`. Pass
+    /// `ALL_SUBRESOURCES` instead of a `Texture::calc_subresource` index when
+    /// every subresource is moving to the same state, to keep the barrier list small.
+    ///
+    /// `resource_state` is `resource`'s owning `Buffer`/`Texture`'s `state` field,
+    /// which the tracker falls back to (and keeps up to date) since its own
+    /// per-recording map is cleared at the start of every command list.
+    pub(super) fn transition_resource(
+        &mut self,
+        resource: native::Resource,
+        resource_state: &AtomicU32,
+        subresource: u32,
+        new_state: d3d12::D3D12_RESOURCE_STATES,
+    ) {
+        self.barrier_tracker.transition(
+            &mut self.temp.barriers,
+            resource,
+            resource_state,
+            subresource,
+            new_state,
+        );
+    }
+
+    /// Flush any barriers accumulated since the last flush into a single
+    /// `ResourceBarrier` call on the currently open command list.
+    pub(super) unsafe fn flush_barriers(&mut self) {
+        if self.temp.barriers.is_empty() {
+            return;
+        }
+        let list = self.list.unwrap();
+        list.ResourceBarrier(self.temp.barriers.len() as u32, self.temp.barriers.as_ptr());
+        self.temp.barriers.clear();
+    }
+
+    /// Pop a free allocator, or create a new one if none are idle yet.
+    unsafe fn acquire_allocator(&mut self) -> Result<native::CommandAllocator, crate::DeviceError> {
+        if let Some(allocator) = self.free_allocators.pop() {
+            return Ok(allocator);
+        }
+        let mut allocator = native::CommandAllocator::null();
+        self.device
+            .CreateCommandAllocator(
+                d3d12::D3D12_COMMAND_LIST_TYPE_DIRECT,
+                &d3d12::ID3D12CommandAllocator::uuidof(),
+                allocator.mut_void(),
+            )
+            .into_device_result("Command allocator creation")?;
+        Ok(allocator)
+    }
+
+    /// Pop a free command list and `Reset` it onto `allocator`, or create a new
+    /// one if none are idle yet.
+    unsafe fn acquire_list(
+        &mut self,
+        allocator: native::CommandAllocator,
+    ) -> Result<native::GraphicsCommandList, crate::DeviceError> {
+        if let Some(list) = self.free_lists.pop() {
+            list.Reset(allocator, ptr::null_mut());
+            return Ok(list);
+        }
+        let mut list = native::GraphicsCommandList::null();
+        self.device
+            .CreateCommandList(
+                0,
+                d3d12::D3D12_COMMAND_LIST_TYPE_DIRECT,
+                allocator,
+                ptr::null_mut(),
+                &d3d12::ID3D12GraphicsCommandList::uuidof(),
+                list.mut_void(),
+            )
+            .into_device_result("Command list creation")?;
+        Ok(list)
+    }
+
+    /// Begin recording a fresh command list, drawing from the reuse pools
+    /// before allocating new D3D12 objects.
+    pub(super) unsafe fn begin_encoding(&mut self) -> Result<(), crate::DeviceError> {
+        self.allocator = self.acquire_allocator()?;
+        self.list = Some(self.acquire_list(self.allocator)?);
+        self.barrier_tracker.clear();
+        // the new list has no root bindings established yet, so every
+        // previously-bound group needs to be pushed down again before it's
+        // used, even if the caller doesn't touch `set_bind_group` this time
+        self.mark_all_groups_dirty();
+        Ok(())
+    }
+
+    /// Called once a command buffer built from this encoder has been submitted
+    /// with `fence_value` as its signal value. The just-recorded list is returned
+    /// to the free-list pool immediately, and `acquire_list` resets it onto
+    /// whichever allocator the next `begin_encoding` picks up — `Reset` on a
+    /// command list only requires it isn't still being recorded, not that the
+    /// GPU has finished with it — while `allocator` itself is
+    /// queued for reclaim once the GPU actually reaches `fence_value`, since an
+    /// allocator cannot be reset while any list recorded from it is still in flight.
+    pub(super) unsafe fn recycle_after_submit(
+        &mut self,
+        list: native::GraphicsCommandList,
+        allocator: native::CommandAllocator,
+        fence_value: crate::FenceValue,
+    ) {
+        self.pending_allocators.push((allocator, fence_value));
+        self.free_lists.push(list);
+    }
+
+    /// Reclaim every pending allocator whose paired fence value has already
+    /// been reached by `Device::idler.fence`, making them available again.
+    ///
+    /// This is the only place an allocator is ever `Reset`: it can't happen
+    /// any earlier, since every list recorded from it must have finished
+    /// executing on the GPU first, which is exactly what reaching
+    /// `fence_value` confirms.
+    pub(super) unsafe fn reclaim_completed(&mut self, completed_value: crate::FenceValue) {
+        let (ready, still_pending): (Vec<_>, Vec<_>) = self
+            .pending_allocators
+            .drain(..)
+            .partition(|&(_, fence_value)| fence_value <= completed_value);
+        self.pending_allocators = still_pending;
+        for (allocator, _) in ready {
+            allocator.Reset();
+            self.free_allocators.push(allocator);
+        }
+    }
+
+    fn mark_all_groups_dirty(&mut self) {
+        if !self.bound_groups.is_empty() {
+            self.dirty_bind_groups = (1u32 << self.bound_groups.len()) - 1;
+        }
+    }
+
+    /// Cache `group`'s descriptor tables and dynamic-buffer addresses for slot
+    /// `index` and mark it dirty, so the next draw/dispatch re-establishes it
+    /// in the root signature via `update_root_elements`.
+    pub(super) fn set_bind_group(
+        &mut self,
+        index: u32,
+        group: &BindGroup,
+        dynamic_offsets: &[wgt::DynamicOffset],
+    ) {
+        let index = index as usize;
+        if self.bound_groups.len() <= index {
+            self.bound_groups.resize(index + 1, None);
+        }
+        let dynamic_buffers = group
+            .dynamic_buffers
+            .iter()
+            .zip(dynamic_offsets)
+            .map(|(&base, &offset)| base + offset as native::GpuAddress)
+            .collect();
+        self.bound_groups[index] = Some(BoundBindGroup {
+            gpu_views: group.gpu_views,
+            gpu_samplers: group.gpu_samplers,
+            dynamic_buffers,
+        });
+        self.dirty_bind_groups |= 1 << index;
+    }
+
+    /// Record the root signature behind a newly-bound pipeline. If it differs
+    /// from the one last used, every bind group must be re-established, since
+    /// D3D12 discards all root bindings whenever the root signature changes.
+    pub(super) fn set_root_signature(&mut self, root_signature: native::RootSignature) {
+        if self.root_signature != root_signature {
+            self.root_signature = root_signature;
+            self.mark_all_groups_dirty();
+        }
+    }
+
+    /// Push every dirty bind group slot down into the currently bound root
+    /// signature: descriptor tables via `Set*RootDescriptorTable`, keyed off
+    /// `layout`'s `BindGroupInfo::tables` and `base_root_index`, and dynamic
+    /// buffers via the matching `Set*Root*View` for each `BufferViewKind`.
+    /// Graphics vs. compute setters are chosen from `self.pass.kind`.
+    pub(super) unsafe fn update_root_elements(&mut self, layout: &PipelineLayout) {
+        if self.dirty_bind_groups == 0 {
+            return;
+        }
+        let list = self.list.unwrap();
+        let is_compute = matches!(self.pass.kind, PassKind::Compute);
+
+        for (index, info) in layout.bind_group_infos.iter().enumerate() {
+            if self.dirty_bind_groups & (1 << index) == 0 {
+                continue;
+            }
+            let group = match self.bound_groups.get(index).and_then(Option::as_ref) {
+                Some(group) => group,
+                None => continue,
+            };
+
+            let mut root_index = info.base_root_index;
+
+            if info.tables.contains(TableTypes::SRV_CBV_UAV) {
+                if is_compute {
+                    list.SetComputeRootDescriptorTable(root_index, group.gpu_views);
+                } else {
+                    list.SetGraphicsRootDescriptorTable(root_index, group.gpu_views);
+                }
+                root_index += 1;
+            }
+            if info.tables.contains(TableTypes::SAMPLERS) {
+                if is_compute {
+                    list.SetComputeRootDescriptorTable(root_index, group.gpu_samplers);
+                } else {
+                    list.SetGraphicsRootDescriptorTable(root_index, group.gpu_samplers);
+                }
+                root_index += 1;
+            }
+
+            for (&kind, &address) in info.dynamic_buffers.iter().zip(group.dynamic_buffers.iter()) {
+                match (kind, is_compute) {
+                    (BufferViewKind::Constant, false) => {
+                        list.SetGraphicsRootConstantBufferView(root_index, address)
+                    }
+                    (BufferViewKind::Constant, true) => {
+                        list.SetComputeRootConstantBufferView(root_index, address)
+                    }
+                    (BufferViewKind::ShaderResource, false) => {
+                        list.SetGraphicsRootShaderResourceView(root_index, address)
+                    }
+                    (BufferViewKind::ShaderResource, true) => {
+                        list.SetComputeRootShaderResourceView(root_index, address)
+                    }
+                    (BufferViewKind::UnorderedAccess, false) => {
+                        list.SetGraphicsRootUnorderedAccessView(root_index, address)
+                    }
+                    (BufferViewKind::UnorderedAccess, true) => {
+                        list.SetComputeRootUnorderedAccessView(root_index, address)
+                    }
+                }
+                root_index += 1;
+            }
+        }
+
+        self.dirty_bind_groups = 0;
+    }
+
+    /// Write a GPU timestamp into `set` at `index`. Timestamps use `EndQuery`
+    /// rather than a dedicated begin/end pair, since D3D12 only supports
+    /// querying a timestamp at a single point.
+    pub(super) unsafe fn write_timestamp(&mut self, set: &QuerySet, index: u32) {
+        self.list
+            .unwrap()
+            .EndQuery(set.raw, d3d12::D3D12_QUERY_TYPE_TIMESTAMP, index);
+    }
+
+    pub(super) unsafe fn begin_query(&mut self, set: &QuerySet, index: u32) {
+        self.list.unwrap().BeginQuery(set.raw, set.raw_ty, index);
+    }
+
+    pub(super) unsafe fn end_query(&mut self, set: &QuerySet, index: u32) {
+        self.list.unwrap().EndQuery(set.raw, set.raw_ty, index);
+    }
+
+    /// Resolve `set`'s queries in `range` into `buffer` at `offset`.
+    pub(super) unsafe fn resolve_query_set(
+        &mut self,
+        set: &QuerySet,
+        range: Range<u32>,
+        buffer: &Buffer,
+        offset: wgt::BufferAddress,
+    ) {
+        self.list.unwrap().ResolveQueryData(
+            set.raw,
+            set.raw_ty,
+            range.start,
+            range.end - range.start,
+            buffer.resource.as_mut_ptr(),
+            offset,
+        );
+    }
+}
+
+/// Cached per-slot binding state, refreshed by `CommandEncoder::set_bind_group`
+/// and consumed by `update_root_elements`.
+#[derive(Clone)]
+pub(super) struct BoundBindGroup {
+    gpu_views: d3d12::D3D12_GPU_DESCRIPTOR_HANDLE,
+    gpu_samplers: d3d12::D3D12_GPU_DESCRIPTOR_HANDLE,
+    dynamic_buffers: Vec<native::GpuAddress>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A null handle is fine here: `BarrierTracker` only ever uses `native::Resource`
+    // as a hash key and as the (un-dereferenced) `pResource` of the barriers it
+    // builds, so this exercises the tracking logic without touching a real device.
+    fn dummy_resource() -> native::Resource {
+        unsafe { mem::zeroed() }
+    }
+
+    #[test]
+    fn bulk_then_individual_then_bulk_excludes_sentinel() {
+        let mut tracker = BarrierTracker::default();
+        let resource = dummy_resource();
+        let resource_state = AtomicU32::new(d3d12::D3D12_RESOURCE_STATE_COMMON);
+        const COMMON: d3d12::D3D12_RESOURCE_STATES = d3d12::D3D12_RESOURCE_STATE_COMMON;
+        const SRV: d3d12::D3D12_RESOURCE_STATES =
+            d3d12::D3D12_RESOURCE_STATE_PIXEL_SHADER_RESOURCE;
+        const UAV: d3d12::D3D12_RESOURCE_STATES = d3d12::D3D12_RESOURCE_STATE_UNORDERED_ACCESS;
+
+        // Bulk-transition the whole resource to SRV.
+        let mut barriers = Vec::new();
+        tracker.transition(&mut barriers, resource, &resource_state, ALL_SUBRESOURCES, SRV);
+
+        // Individually move subresource 5 to UAV, leaving the rest implicitly at SRV.
+        barriers.clear();
+        tracker.transition(&mut barriers, resource, &resource_state, 5, UAV);
+
+        // Bulk-transition back to COMMON: subresource 5 (UAV) and the
+        // ALL_SUBRESOURCES sentinel (SRV) now disagree.
+        barriers.clear();
+        tracker.transition_all(&mut barriers, resource, &resource_state, COMMON);
+
+        // Every emitted barrier must agree on subresource 5's StateBefore, and
+        // the sentinel must not be emitted as if it were a literal subresource.
+        for barrier in &barriers {
+            let transition = unsafe { barrier.u.Transition() };
+            assert_ne!(
+                transition.Subresource, ALL_SUBRESOURCES,
+                "ALL_SUBRESOURCES sentinel must not be combined with per-subresource barriers"
+            );
+            if transition.Subresource == 5 {
+                assert_eq!(transition.StateBefore, UAV);
+            }
+        }
+    }
+
+    #[test]
+    fn first_use_after_clear_barriers_from_the_resource_s_last_known_state() {
+        // Simulate a render target used as an SRV in one command list, then
+        // drawn into as a render target in the next: `clear()` (called by
+        // `begin_encoding` for every new recording) must not make the tracker
+        // think the resource's first use in the new list needs no barrier.
+        let resource = dummy_resource();
+        let resource_state =
+            AtomicU32::new(d3d12::D3D12_RESOURCE_STATE_PIXEL_SHADER_RESOURCE);
+
+        let mut tracker = BarrierTracker::default();
+        let mut barriers = Vec::new();
+        tracker.transition(
+            &mut barriers,
+            resource,
+            &resource_state,
+            ALL_SUBRESOURCES,
+            d3d12::D3D12_RESOURCE_STATE_PIXEL_SHADER_RESOURCE,
+        );
+        assert!(barriers.is_empty(), "no-op transition should emit nothing");
+
+        tracker.clear();
+        barriers.clear();
+        tracker.transition(
+            &mut barriers,
+            resource,
+            &resource_state,
+            ALL_SUBRESOURCES,
+            d3d12::D3D12_RESOURCE_STATE_RENDER_TARGET,
+        );
+
+        assert_eq!(barriers.len(), 1);
+        let transition = unsafe { barriers[0].u.Transition() };
+        assert_eq!(
+            transition.StateBefore,
+            d3d12::D3D12_RESOURCE_STATE_PIXEL_SHADER_RESOURCE
+        );
+        assert_eq!(transition.StateAfter, d3d12::D3D12_RESOURCE_STATE_RENDER_TARGET);
+    }
+}
@@ -0,0 +1,231 @@
+use super::native;
+use parking_lot::Mutex;
+use winapi::um::d3d12;
+
+/// A single CPU-visible descriptor, as handed out by a `CpuPool`. These back
+/// `TextureView`/`Sampler` and get copied into a shader-visible `GeneralHeap`
+/// when a bind group referencing them is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) struct Handle {
+    pub(super) raw: d3d12::D3D12_CPU_DESCRIPTOR_HANDLE,
+    heap_index: usize,
+    index: u32,
+}
+
+const HEAP_CHUNK_SIZE: u32 = 256;
+
+struct CpuHeap {
+    raw: native::DescriptorHeap,
+    start: d3d12::D3D12_CPU_DESCRIPTOR_HANDLE,
+    free_indices: Vec<u32>,
+}
+
+/// A growable pool of CPU-only descriptor heaps (RTV/DSV/SRV-UAV/Sampler),
+/// handing out single descriptors with a simple free list per chunk.
+pub(super) struct CpuPool {
+    device: native::Device,
+    ty: native::DescriptorHeapType,
+    handle_size: u32,
+    heaps: Vec<CpuHeap>,
+}
+
+impl CpuPool {
+    pub(super) fn new(device: native::Device, ty: native::DescriptorHeapType) -> Self {
+        CpuPool {
+            device,
+            ty,
+            handle_size: device.get_descriptor_increment_size(ty),
+            heaps: Vec::new(),
+        }
+    }
+
+    pub(super) fn alloc_handle(&mut self) -> Handle {
+        for (heap_index, heap) in self.heaps.iter_mut().enumerate() {
+            if let Some(index) = heap.free_indices.pop() {
+                return Handle {
+                    raw: d3d12::D3D12_CPU_DESCRIPTOR_HANDLE {
+                        ptr: heap.start.ptr + (index * self.handle_size) as usize,
+                    },
+                    heap_index,
+                    index,
+                };
+            }
+        }
+
+        let heap = native::DescriptorHeap::create(self.device, self.ty, false, HEAP_CHUNK_SIZE);
+        let start = heap.start_cpu_descriptor();
+        let heap_index = self.heaps.len();
+        // index 0 is handed out immediately below, so the new chunk's free list
+        // only needs to seed the remaining `HEAP_CHUNK_SIZE - 1` indices
+        let free_indices = (1..HEAP_CHUNK_SIZE).rev().collect();
+        self.heaps.push(CpuHeap {
+            raw: heap,
+            start,
+            free_indices,
+        });
+
+        Handle {
+            raw: start,
+            heap_index,
+            index: 0,
+        }
+    }
+
+    pub(super) fn free_handle(&mut self, handle: Handle) {
+        self.heaps[handle.heap_index].free_indices.push(handle.index);
+    }
+}
+
+struct FreeRange {
+    start: u32,
+    count: u32,
+}
+
+/// A coalescing free-list suballocator over the linear index space of a
+/// shader-visible descriptor heap, handing out contiguous runs of descriptors
+/// to bind groups and reclaiming them on destroy.
+struct RangeAllocator {
+    free_ranges: Vec<FreeRange>,
+}
+
+impl RangeAllocator {
+    fn new(capacity: u32) -> Self {
+        RangeAllocator {
+            free_ranges: vec![FreeRange {
+                start: 0,
+                count: capacity,
+            }],
+        }
+    }
+
+    fn allocate(&mut self, count: u32) -> Option<u32> {
+        let index = self.free_ranges.iter().position(|r| r.count >= count)?;
+        let range = &mut self.free_ranges[index];
+        let start = range.start;
+        if range.count == count {
+            self.free_ranges.remove(index);
+        } else {
+            range.start += count;
+            range.count -= count;
+        }
+        Some(start)
+    }
+
+    fn free(&mut self, start: u32, count: u32) {
+        let index = self.free_ranges.partition_point(|r| r.start < start);
+        self.free_ranges.insert(index, FreeRange { start, count });
+        if index + 1 < self.free_ranges.len()
+            && self.free_ranges[index].start + self.free_ranges[index].count
+                == self.free_ranges[index + 1].start
+        {
+            self.free_ranges[index].count += self.free_ranges.remove(index + 1).count;
+        }
+        if index > 0
+            && self.free_ranges[index - 1].start + self.free_ranges[index - 1].count
+                == self.free_ranges[index].start
+        {
+            self.free_ranges[index - 1].count += self.free_ranges.remove(index).count;
+        }
+    }
+}
+
+/// A contiguous range of descriptors suballocated out of a `GeneralHeap`,
+/// recorded on a `BindGroup` so it can be returned to the heap on destroy.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Allocation {
+    start_index: u32,
+    count: u32,
+}
+
+/// One monolithic shader-visible descriptor heap (CBV/SRV/UAV or Sampler),
+/// suballocated into per-bind-group ranges.
+pub(super) struct GeneralHeap {
+    pub(super) raw: native::DescriptorHeap,
+    ty: native::DescriptorHeapType,
+    handle_size: u32,
+    total_handles: u32,
+    start_cpu: d3d12::D3D12_CPU_DESCRIPTOR_HANDLE,
+    start_gpu: d3d12::D3D12_GPU_DESCRIPTOR_HANDLE,
+    allocator: Mutex<RangeAllocator>,
+}
+
+impl GeneralHeap {
+    pub(super) fn new(device: native::Device, ty: native::DescriptorHeapType, total_handles: u32) -> Self {
+        let raw = native::DescriptorHeap::create(device, ty, true, total_handles);
+        GeneralHeap {
+            raw,
+            ty,
+            handle_size: device.get_descriptor_increment_size(ty),
+            total_handles,
+            start_cpu: raw.start_cpu_descriptor(),
+            start_gpu: raw.start_gpu_descriptor(),
+            allocator: Mutex::new(RangeAllocator::new(total_handles)),
+        }
+    }
+
+    fn cpu_descriptor_at(&self, index: u32) -> d3d12::D3D12_CPU_DESCRIPTOR_HANDLE {
+        d3d12::D3D12_CPU_DESCRIPTOR_HANDLE {
+            ptr: self.start_cpu.ptr + (index * self.handle_size) as usize,
+        }
+    }
+
+    fn gpu_descriptor_at(&self, index: u32) -> d3d12::D3D12_GPU_DESCRIPTOR_HANDLE {
+        d3d12::D3D12_GPU_DESCRIPTOR_HANDLE {
+            ptr: self.start_gpu.ptr + (index * self.handle_size) as u64,
+        }
+    }
+
+    /// Reserve `count` contiguous descriptors for a bind group and copy
+    /// `sources` into them. When `avoid_cpu_descriptor_overwrites` is set
+    /// (the WARP workaround), the sources are first re-staged into a fresh
+    /// region of `staging_pool` rather than being copied in place, since
+    /// WARP's runtime keeps reading from the CPU descriptors named in a
+    /// `CopyDescriptors` call even after the call returns. The staged handles
+    /// are returned so the caller can keep them alive on the `BindGroup` and
+    /// free them back to `staging_pool` once the group is destroyed; they're
+    /// empty when no staging was needed.
+    pub(super) unsafe fn allocate(
+        &self,
+        device: native::Device,
+        sources: &[d3d12::D3D12_CPU_DESCRIPTOR_HANDLE],
+        avoid_cpu_descriptor_overwrites: bool,
+        staging_pool: &mut CpuPool,
+    ) -> Option<(Allocation, d3d12::D3D12_GPU_DESCRIPTOR_HANDLE, Vec<Handle>)> {
+        let count = sources.len() as u32;
+        let start_index = self.allocator.lock().allocate(count)?;
+
+        let staged;
+        let (sources, staged_handles): (&[d3d12::D3D12_CPU_DESCRIPTOR_HANDLE], Vec<Handle>) =
+            if avoid_cpu_descriptor_overwrites {
+                let handles = sources
+                    .iter()
+                    .map(|&source| {
+                        let handle = staging_pool.alloc_handle();
+                        device.CopyDescriptorsSimple(1, handle.raw, source, self.ty);
+                        handle
+                    })
+                    .collect::<Vec<_>>();
+                staged = handles.iter().map(|handle| handle.raw).collect::<Vec<_>>();
+                (&staged, handles)
+            } else {
+                (sources, Vec::new())
+            };
+
+        for (i, &source) in sources.iter().enumerate() {
+            device.CopyDescriptorsSimple(1, self.cpu_descriptor_at(start_index + i as u32), source, self.ty);
+        }
+
+        Some((
+            Allocation { start_index, count },
+            self.gpu_descriptor_at(start_index),
+            staged_handles,
+        ))
+    }
+
+    /// Return a bind group's descriptor range to the free list.
+    pub(super) fn free(&self, allocation: Allocation) {
+        self.allocator
+            .lock()
+            .free(allocation.start_index, allocation.count);
+    }
+}
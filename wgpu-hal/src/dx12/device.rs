@@ -0,0 +1,507 @@
+use super::{conv, native, Device, HResult as _, PrivateCapabilities};
+use std::{mem, ptr, sync::atomic::AtomicU32};
+use winapi::{shared::dxgitype, um::d3d12, Interface as _};
+
+/// Size of a single heap block. Individual allocations are suballocated out of
+/// blocks this large; anything bigger falls back to its own committed resource,
+/// since the 64 KB / 4 MB heap alignment requirements make dedicating a whole
+/// block to it no worse than a committed resource would have been anyway.
+const HEAP_BLOCK_SIZE: u64 = 64 << 20; // 64 MB
+
+/// Which kind of resource a pool's placed resources may hold. D3D12 resource
+/// heap tier 1 requires buffers, render-target/depth-stencil textures, and other
+/// textures to live in separate heaps; tier 2 (`heterogeneous_resource_heaps`)
+/// allows them to share one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ResourceCategory {
+    Buffer,
+    RenderTarget,
+    Texture,
+}
+
+impl ResourceCategory {
+    fn heap_flags(self) -> d3d12::D3D12_HEAP_FLAGS {
+        match self {
+            ResourceCategory::Buffer => d3d12::D3D12_HEAP_FLAG_ALLOW_ONLY_BUFFERS,
+            ResourceCategory::RenderTarget => d3d12::D3D12_HEAP_FLAG_ALLOW_ONLY_RT_DS_TEXTURES,
+            ResourceCategory::Texture => d3d12::D3D12_HEAP_FLAG_ALLOW_ONLY_NON_RT_DS_TEXTURES,
+        }
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+struct FreeBlock {
+    offset: u64,
+    size: u64,
+}
+
+/// One `ID3D12Heap` plus a free-list suballocator over it.
+struct MemoryHeap {
+    raw: native::Heap,
+    size: u64,
+    free_blocks: Vec<FreeBlock>,
+}
+
+impl MemoryHeap {
+    fn try_alloc(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let index = self.free_blocks.iter().position(|block| {
+            let aligned_offset = align_up(block.offset, alignment);
+            aligned_offset + size <= block.offset + block.size
+        })?;
+
+        let block = &self.free_blocks[index];
+        let aligned_offset = align_up(block.offset, alignment);
+        let head_padding = aligned_offset - block.offset;
+        let tail = (block.offset + block.size) - (aligned_offset + size);
+
+        self.free_blocks.remove(index);
+        if head_padding > 0 {
+            self.free_blocks.insert(
+                index,
+                FreeBlock {
+                    offset: block.offset,
+                    size: head_padding,
+                },
+            );
+        }
+        if tail > 0 {
+            self.free_blocks.insert(
+                index + head_padding.min(1) as usize,
+                FreeBlock {
+                    offset: aligned_offset + size,
+                    size: tail,
+                },
+            );
+        }
+
+        Some(aligned_offset)
+    }
+
+    fn free(&mut self, offset: u64, size: u64) {
+        let index = self
+            .free_blocks
+            .partition_point(|block| block.offset < offset);
+        self.free_blocks.insert(index, FreeBlock { offset, size });
+
+        // coalesce with the following neighbor first so the preceding merge below
+        // only ever has to look at one, now possibly-larger, block
+        if index + 1 < self.free_blocks.len()
+            && self.free_blocks[index].offset + self.free_blocks[index].size
+                == self.free_blocks[index + 1].offset
+        {
+            self.free_blocks[index].size += self.free_blocks.remove(index + 1).size;
+        }
+        if index > 0
+            && self.free_blocks[index - 1].offset + self.free_blocks[index - 1].size
+                == self.free_blocks[index].offset
+        {
+            self.free_blocks[index - 1].size += self.free_blocks.remove(index).size;
+        }
+    }
+}
+
+/// All the heaps backing one `(D3D12_HEAP_TYPE, ResourceCategory)` combination.
+/// `heap_flags` is passed in rather than derived from a `ResourceCategory` at
+/// use time, since a pool that's had other categories rerouted into it by
+/// `MemoryManager::pool_for` (tier 2 hardware) needs looser flags than its
+/// nominal category would otherwise give it.
+struct MemoryPool {
+    heap_type: d3d12::D3D12_HEAP_TYPE,
+    heap_flags: d3d12::D3D12_HEAP_FLAGS,
+    heaps: Vec<MemoryHeap>,
+}
+
+impl MemoryPool {
+    fn new(heap_type: d3d12::D3D12_HEAP_TYPE, heap_flags: d3d12::D3D12_HEAP_FLAGS) -> Self {
+        MemoryPool {
+            heap_type,
+            heap_flags,
+            heaps: Vec::new(),
+        }
+    }
+
+    unsafe fn allocate(
+        &mut self,
+        raw_device: native::Device,
+        size: u64,
+        alignment: u64,
+    ) -> Result<(usize, u64), crate::DeviceError> {
+        for (heap_index, heap) in self.heaps.iter_mut().enumerate() {
+            if let Some(offset) = heap.try_alloc(size, alignment) {
+                return Ok((heap_index, offset));
+            }
+        }
+
+        let block_size = size.max(HEAP_BLOCK_SIZE);
+        let desc = d3d12::D3D12_HEAP_DESC {
+            SizeInBytes: block_size,
+            Properties: d3d12::D3D12_HEAP_PROPERTIES {
+                Type: self.heap_type,
+                CPUPageProperty: d3d12::D3D12_CPU_PAGE_PROPERTY_UNKNOWN,
+                MemoryPoolPreference: d3d12::D3D12_MEMORY_POOL_UNKNOWN,
+                CreationNodeMask: 0,
+                VisibleNodeMask: 0,
+            },
+            Alignment: d3d12::D3D12_DEFAULT_MSAA_RESOURCE_PLACEMENT_ALIGNMENT as u64,
+            Flags: self.heap_flags,
+        };
+
+        let mut raw = native::Heap::null();
+        raw_device
+            .CreateHeap(&desc, &d3d12::ID3D12Heap::uuidof(), raw.mut_void())
+            .into_device_result("Heap creation")?;
+
+        self.heaps.push(MemoryHeap {
+            raw,
+            size: block_size,
+            free_blocks: vec![FreeBlock {
+                offset: 0,
+                size: block_size,
+            }],
+        });
+        Ok((self.heaps.len() - 1, 0))
+    }
+
+    fn free(&mut self, heap_index: usize, offset: u64, size: u64) {
+        self.heaps[heap_index].free(offset, size);
+    }
+}
+
+/// Where a placed resource's backing memory lives within the `Device`'s
+/// memory pools. `Buffer`/`Texture` store `Option<AllocationHandle>`; `None`
+/// covers both resources that are externally owned (e.g. swapchain back
+/// buffers) and the committed-resource fallback for allocations too large to
+/// share a block, since neither has a range to give back on destroy.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct AllocationHandle {
+    heap_type: d3d12::D3D12_HEAP_TYPE,
+    category: PoolCategory,
+    heap_index: usize,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum PoolCategory {
+    Buffer,
+    RenderTarget,
+    Texture,
+}
+
+impl From<PoolCategory> for ResourceCategory {
+    fn from(category: PoolCategory) -> Self {
+        match category {
+            PoolCategory::Buffer => ResourceCategory::Buffer,
+            PoolCategory::RenderTarget => ResourceCategory::RenderTarget,
+            PoolCategory::Texture => ResourceCategory::Texture,
+        }
+    }
+}
+
+/// Owns the placed-resource heaps for every `(D3D12_HEAP_TYPE, PoolCategory)`
+/// combination the device needs, collapsing categories that can share a heap
+/// when the adapter reports `heterogeneous_resource_heaps`.
+pub(super) struct MemoryManager {
+    heterogeneous_resource_heaps: bool,
+    default_buffers: MemoryPool,
+    default_render_targets: MemoryPool,
+    default_textures: MemoryPool,
+    upload_buffers: MemoryPool,
+    readback_buffers: MemoryPool,
+}
+
+impl MemoryManager {
+    pub(super) fn new(private_caps: PrivateCapabilities) -> Self {
+        // On tier 2 (heterogeneous) hardware every default-heap category is
+        // routed into `default_buffers` by `pool_for` below, so that pool's
+        // heaps must be able to hold buffers, RT/DS textures, and other
+        // textures all at once rather than just buffers.
+        let default_buffers_flags = if private_caps.heterogeneous_resource_heaps {
+            d3d12::D3D12_HEAP_FLAG_ALLOW_ALL_BUFFERS_AND_TEXTURES
+        } else {
+            ResourceCategory::Buffer.heap_flags()
+        };
+        MemoryManager {
+            heterogeneous_resource_heaps: private_caps.heterogeneous_resource_heaps,
+            default_buffers: MemoryPool::new(d3d12::D3D12_HEAP_TYPE_DEFAULT, default_buffers_flags),
+            default_render_targets: MemoryPool::new(
+                d3d12::D3D12_HEAP_TYPE_DEFAULT,
+                ResourceCategory::RenderTarget.heap_flags(),
+            ),
+            default_textures: MemoryPool::new(
+                d3d12::D3D12_HEAP_TYPE_DEFAULT,
+                ResourceCategory::Texture.heap_flags(),
+            ),
+            upload_buffers: MemoryPool::new(d3d12::D3D12_HEAP_TYPE_UPLOAD, ResourceCategory::Buffer.heap_flags()),
+            readback_buffers: MemoryPool::new(
+                d3d12::D3D12_HEAP_TYPE_READBACK,
+                ResourceCategory::Buffer.heap_flags(),
+            ),
+        }
+    }
+
+    fn pool_for(&mut self, heap_type: d3d12::D3D12_HEAP_TYPE, category: PoolCategory) -> &mut MemoryPool {
+        // Tier 2 (heterogeneous) hardware lets buffers, RT/DS textures, and other
+        // textures share one heap; route every category through the buffer
+        // pool for its heap type (UPLOAD/READBACK heaps only ever see buffers
+        // in this backend anyway). `default_buffers` was built with looser
+        // heap flags above to actually allow holding those other categories.
+        let category = if self.heterogeneous_resource_heaps {
+            PoolCategory::Buffer
+        } else {
+            category
+        };
+        match (heap_type, category) {
+            (d3d12::D3D12_HEAP_TYPE_UPLOAD, _) => &mut self.upload_buffers,
+            (d3d12::D3D12_HEAP_TYPE_READBACK, _) => &mut self.readback_buffers,
+            (_, PoolCategory::RenderTarget) => &mut self.default_render_targets,
+            (_, PoolCategory::Texture) => &mut self.default_textures,
+            (_, PoolCategory::Buffer) => &mut self.default_buffers,
+        }
+    }
+
+    /// Suballocate `size` bytes (aligned to `alignment`) of `category` memory out
+    /// of `heap_type`, creating a new 64 MB heap block if none of the existing
+    /// ones have room. Allocations larger than a block get their own dedicated
+    /// heap rather than being denied.
+    pub(super) unsafe fn allocate(
+        &mut self,
+        raw_device: native::Device,
+        heap_type: d3d12::D3D12_HEAP_TYPE,
+        category: PoolCategory,
+        size: u64,
+        alignment: u64,
+    ) -> Result<(native::Heap, u64, AllocationHandle), crate::DeviceError> {
+        let pool = self.pool_for(heap_type, category);
+        let (heap_index, offset) = pool.allocate(raw_device, size, alignment)?;
+        Ok((
+            pool.heaps[heap_index].raw,
+            offset,
+            AllocationHandle {
+                heap_type,
+                category,
+                heap_index,
+                offset,
+                size,
+            },
+        ))
+    }
+
+    /// Return a previously-allocated range to its pool's free list.
+    pub(super) fn free(&mut self, allocation: AllocationHandle) {
+        self.pool_for(allocation.heap_type, allocation.category).free(
+            allocation.heap_index,
+            allocation.offset,
+            allocation.size,
+        );
+    }
+}
+
+fn heap_properties(heap_type: d3d12::D3D12_HEAP_TYPE) -> d3d12::D3D12_HEAP_PROPERTIES {
+    d3d12::D3D12_HEAP_PROPERTIES {
+        Type: heap_type,
+        CPUPageProperty: d3d12::D3D12_CPU_PAGE_PROPERTY_UNKNOWN,
+        MemoryPoolPreference: d3d12::D3D12_MEMORY_POOL_UNKNOWN,
+        CreationNodeMask: 0,
+        VisibleNodeMask: 0,
+    }
+}
+
+impl Device {
+    /// Allocate a `size`-byte buffer in `heap_type`, suballocating it out of
+    /// `mem_allocator`'s pools via `CreatePlacedResource` when it fits a block,
+    /// or falling back to a dedicated committed resource when it doesn't.
+    pub(super) unsafe fn create_buffer(
+        &self,
+        size: wgt::BufferAddress,
+        heap_type: d3d12::D3D12_HEAP_TYPE,
+    ) -> Result<super::Buffer, crate::DeviceError> {
+        let mut raw_desc: d3d12::D3D12_RESOURCE_DESC = mem::zeroed();
+        raw_desc.Dimension = d3d12::D3D12_RESOURCE_DIMENSION_BUFFER;
+        raw_desc.Width = size;
+        raw_desc.Height = 1;
+        raw_desc.DepthOrArraySize = 1;
+        raw_desc.MipLevels = 1;
+        raw_desc.SampleDesc = dxgitype::DXGI_SAMPLE_DESC {
+            Count: 1,
+            Quality: 0,
+        };
+        raw_desc.Layout = d3d12::D3D12_TEXTURE_LAYOUT_ROW_MAJOR;
+        raw_desc.Flags = if heap_type == d3d12::D3D12_HEAP_TYPE_DEFAULT {
+            d3d12::D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS
+        } else {
+            d3d12::D3D12_RESOURCE_FLAG_NONE
+        };
+
+        let info = self.raw.GetResourceAllocationInfo(0, 1, &raw_desc);
+        let initial_state = match heap_type {
+            d3d12::D3D12_HEAP_TYPE_UPLOAD => d3d12::D3D12_RESOURCE_STATE_GENERIC_READ,
+            d3d12::D3D12_HEAP_TYPE_READBACK => d3d12::D3D12_RESOURCE_STATE_COPY_DEST,
+            _ => d3d12::D3D12_RESOURCE_STATE_COMMON,
+        };
+
+        let mut resource = native::Resource::null();
+        let allocation = if info.SizeInBytes > HEAP_BLOCK_SIZE {
+            self.raw
+                .CreateCommittedResource(
+                    &heap_properties(heap_type),
+                    d3d12::D3D12_HEAP_FLAG_NONE,
+                    &raw_desc,
+                    initial_state,
+                    ptr::null(),
+                    &d3d12::ID3D12Resource::uuidof(),
+                    resource.mut_void(),
+                )
+                .into_device_result("Committed buffer creation")?;
+            None
+        } else {
+            let (heap, offset, allocation) = self.mem_allocator.lock().allocate(
+                self.raw,
+                heap_type,
+                PoolCategory::Buffer,
+                info.SizeInBytes,
+                info.Alignment,
+            )?;
+            self.raw
+                .CreatePlacedResource(
+                    heap,
+                    offset,
+                    &raw_desc,
+                    initial_state,
+                    ptr::null(),
+                    &d3d12::ID3D12Resource::uuidof(),
+                    resource.mut_void(),
+                )
+                .into_device_result("Placed buffer creation")?;
+            Some(allocation)
+        };
+
+        Ok(super::Buffer {
+            resource,
+            size,
+            allocation,
+            state: AtomicU32::new(initial_state),
+        })
+    }
+
+    /// Release `buffer`'s resource and, if it was suballocated rather than
+    /// committed, return its range to the owning pool's free list.
+    pub(super) unsafe fn destroy_buffer(&self, buffer: super::Buffer) {
+        buffer.resource.destroy();
+        if let Some(allocation) = buffer.allocation {
+            self.mem_allocator.lock().free(allocation);
+        }
+    }
+
+    /// Allocate a 2D/3D texture, suballocating it out of `mem_allocator`'s
+    /// pools via `CreatePlacedResource` when it fits a block, or falling back
+    /// to a dedicated committed resource when it doesn't. Render targets and
+    /// depth-stencil textures are routed to their own pool, since heap tier 1
+    /// hardware cannot mix them with regular textures or buffers in one heap.
+    pub(super) unsafe fn create_texture(
+        &self,
+        format: wgt::TextureFormat,
+        dimension: wgt::TextureDimension,
+        size: wgt::Extent3d,
+        mip_level_count: u32,
+        sample_count: u32,
+        is_render_target: bool,
+    ) -> Result<super::Texture, crate::DeviceError> {
+        let mut raw_desc: d3d12::D3D12_RESOURCE_DESC = mem::zeroed();
+        raw_desc.Dimension = conv::map_texture_dimension(dimension);
+        raw_desc.Width = size.width as u64;
+        raw_desc.Height = size.height;
+        raw_desc.DepthOrArraySize = size.depth_or_array_layers as u16;
+        raw_desc.MipLevels = mip_level_count as u16;
+        raw_desc.Format = conv::map_texture_format(format);
+        raw_desc.SampleDesc = dxgitype::DXGI_SAMPLE_DESC {
+            Count: sample_count,
+            Quality: 0,
+        };
+        raw_desc.Layout = d3d12::D3D12_TEXTURE_LAYOUT_UNKNOWN;
+        raw_desc.Flags = if is_render_target {
+            d3d12::D3D12_RESOURCE_FLAG_ALLOW_RENDER_TARGET
+        } else {
+            d3d12::D3D12_RESOURCE_FLAG_NONE
+        };
+
+        let info = self.raw.GetResourceAllocationInfo(0, 1, &raw_desc);
+        let category = if is_render_target {
+            PoolCategory::RenderTarget
+        } else {
+            PoolCategory::Texture
+        };
+
+        let mut resource = native::Resource::null();
+        let allocation = if info.SizeInBytes > HEAP_BLOCK_SIZE {
+            self.raw
+                .CreateCommittedResource(
+                    &heap_properties(d3d12::D3D12_HEAP_TYPE_DEFAULT),
+                    d3d12::D3D12_HEAP_FLAG_NONE,
+                    &raw_desc,
+                    d3d12::D3D12_RESOURCE_STATE_COMMON,
+                    ptr::null(),
+                    &d3d12::ID3D12Resource::uuidof(),
+                    resource.mut_void(),
+                )
+                .into_device_result("Committed texture creation")?;
+            None
+        } else {
+            let (heap, offset, allocation) = self.mem_allocator.lock().allocate(
+                self.raw,
+                d3d12::D3D12_HEAP_TYPE_DEFAULT,
+                category,
+                info.SizeInBytes,
+                info.Alignment,
+            )?;
+            self.raw
+                .CreatePlacedResource(
+                    heap,
+                    offset,
+                    &raw_desc,
+                    d3d12::D3D12_RESOURCE_STATE_COMMON,
+                    ptr::null(),
+                    &d3d12::ID3D12Resource::uuidof(),
+                    resource.mut_void(),
+                )
+                .into_device_result("Placed texture creation")?;
+            Some(allocation)
+        };
+
+        Ok(super::Texture {
+            resource,
+            format,
+            dimension,
+            size,
+            mip_level_count,
+            sample_count,
+            allocation,
+            state: AtomicU32::new(d3d12::D3D12_RESOURCE_STATE_COMMON),
+        })
+    }
+
+    /// Release `texture`'s resource and, if it was suballocated rather than
+    /// committed, return its range to the owning pool's free list.
+    pub(super) unsafe fn destroy_texture(&self, texture: super::Texture) {
+        texture.resource.destroy();
+        if let Some(allocation) = texture.allocation {
+            self.mem_allocator.lock().free(allocation);
+        }
+    }
+}
+
+/// Query `queue`'s GPU timestamp frequency and convert it to the
+/// nanoseconds-per-tick period that `Queue::timestamp_period` stores, so
+/// `write_timestamp` results can be converted to wall-clock durations.
+pub(super) unsafe fn query_timestamp_period(
+    queue: native::CommandQueue,
+) -> Result<f32, crate::DeviceError> {
+    let mut frequency = 0u64;
+    queue
+        .GetTimestampFrequency(&mut frequency)
+        .into_device_result("GetTimestampFrequency")?;
+    Ok(1.0e9 / frequency as f32)
+}
@@ -16,7 +16,11 @@ mod instance;
 
 use arrayvec::ArrayVec;
 use parking_lot::Mutex;
-use std::{borrow::Cow, fmt, mem, ptr, sync::Arc};
+use std::{
+    borrow::Cow,
+    fmt, mem, ptr,
+    sync::{atomic::AtomicU32, Arc},
+};
 use winapi::{
     shared::{dxgi, dxgi1_2, dxgi1_4, dxgiformat, dxgitype, windef, winerror},
     um::{d3d12, synchapi, winbase, winnt},
@@ -112,6 +116,9 @@ struct SwapChain {
     resources: Vec<native::Resource>,
     waitable: winnt::HANDLE,
     acquired_count: usize,
+    present_mode: wgt::PresentMode,
+    format: wgt::TextureFormat,
+    size: wgt::Extent3d,
 }
 
 pub struct Surface {
@@ -200,6 +207,8 @@ pub struct Device {
     dsv_pool: Mutex<descriptor::CpuPool>,
     srv_uav_pool: Mutex<descriptor::CpuPool>,
     sampler_pool: Mutex<descriptor::CpuPool>,
+    // placed-resource heaps, suballocated for buffers and textures
+    mem_allocator: Mutex<device::MemoryManager>,
     // library
     library: Arc<native::D3D12Lib>,
 }
@@ -209,6 +218,10 @@ unsafe impl Sync for Device {}
 
 pub struct Queue {
     raw: native::CommandQueue,
+    /// Nanoseconds per GPU timestamp tick, derived from `GetTimestampFrequency`
+    /// at device creation, so `write_timestamp` results can be converted to
+    /// wall-clock time.
+    timestamp_period: f32,
 }
 
 unsafe impl Send for Queue {}
@@ -274,6 +287,26 @@ pub struct CommandEncoder {
     free_lists: Vec<native::GraphicsCommandList>,
     pass: PassState,
     temp: Temp,
+    /// Tracks the current `D3D12_RESOURCE_STATES` of every resource (and subresource)
+    /// touched by this encoder, so that barriers can be emitted lazily and coalesced.
+    barrier_tracker: command::BarrierTracker,
+    /// Allocators that aren't backing any in-flight command list and can be
+    /// handed out to the next `begin_encoding` right away.
+    free_allocators: Vec<native::CommandAllocator>,
+    /// Allocators whose recorded lists have been submitted but whose GPU work
+    /// hasn't finished yet; each can only be `Reset` once `idler.fence`'s
+    /// completed value reaches the paired `FenceValue`.
+    pending_allocators: Vec<(native::CommandAllocator, crate::FenceValue)>,
+    /// Per-bind-group-slot state cached from the last `set_bind_group`, pushed
+    /// into the root signature by `update_root_elements` at draw/dispatch time.
+    bound_groups: Vec<Option<command::BoundBindGroup>>,
+    /// Bitmask of bind group slots that still need (re-)establishing in the
+    /// root signature before the next draw or dispatch.
+    dirty_bind_groups: u32,
+    /// Root signature of the currently bound pipeline; used to detect
+    /// pipeline-layout changes that require rebinding every group, since
+    /// D3D12 invalidates all root bindings when the root signature changes.
+    root_signature: native::RootSignature,
 }
 
 unsafe impl Send for CommandEncoder {}
@@ -290,6 +323,17 @@ unsafe impl Sync for CommandBuffer {}
 pub struct Buffer {
     resource: native::Resource,
     size: wgt::BufferAddress,
+    /// `Some` when `resource` is a placed resource suballocated out of one of the
+    /// `Device`'s memory pools; `None` for the committed-resource fallback, in
+    /// which case `destroy` releases the resource directly instead of freeing a range.
+    allocation: Option<device::AllocationHandle>,
+    /// The resource's actual last-known `D3D12_RESOURCE_STATES`, starting from
+    /// the state it was created in. A `BarrierTracker` is cleared at the start
+    /// of every command list recording, so this is what lets the first
+    /// transition recorded against this buffer in a *new* list still compute a
+    /// correct `StateBefore`, instead of assuming "not seen by this tracker yet"
+    /// means no barrier is needed.
+    state: AtomicU32,
 }
 
 unsafe impl Send for Buffer {}
@@ -316,6 +360,10 @@ pub struct Texture {
     size: wgt::Extent3d,
     mip_level_count: u32,
     sample_count: u32,
+    /// See `Buffer::allocation`.
+    allocation: Option<device::AllocationHandle>,
+    /// See `Buffer::state`.
+    state: AtomicU32,
 }
 
 unsafe impl Send for Texture {}
@@ -394,6 +442,17 @@ pub struct BindGroup {
     gpu_views: d3d12::D3D12_GPU_DESCRIPTOR_HANDLE,
     gpu_samplers: d3d12::D3D12_GPU_DESCRIPTOR_HANDLE,
     dynamic_buffers: Vec<native::GpuAddress>,
+    /// Where `gpu_views`/`gpu_samplers` live in `DeviceShared::heap_views`/
+    /// `heap_samplers`, so `destroy` can return the range to the heap's
+    /// suballocator. `None` when this group doesn't use that heap at all.
+    views_range: Option<descriptor::Allocation>,
+    samplers_range: Option<descriptor::Allocation>,
+    /// CPU descriptors staged into `Device::srv_uav_pool`/`sampler_pool` for
+    /// the WARP `avoid_cpu_descriptor_overwrites` workaround; empty when the
+    /// workaround isn't active. Kept alive here since WARP reads them even
+    /// after `CopyDescriptors`, and freed by `destroy_descriptors`.
+    staged_view_handles: Vec<descriptor::Handle>,
+    staged_sampler_handles: Vec<descriptor::Handle>,
 }
 
 impl fmt::Debug for BindGroup {
@@ -406,6 +465,30 @@ impl fmt::Debug for BindGroup {
     }
 }
 
+impl BindGroup {
+    /// Return this bind group's descriptor ranges, and any CPU descriptors
+    /// staged for the WARP workaround, to their pools' free lists.
+    unsafe fn destroy_descriptors(
+        &self,
+        shared: &DeviceShared,
+        srv_uav_pool: &mut descriptor::CpuPool,
+        sampler_pool: &mut descriptor::CpuPool,
+    ) {
+        if let Some(range) = self.views_range {
+            shared.heap_views.free(range);
+        }
+        if let Some(range) = self.samplers_range {
+            shared.heap_samplers.free(range);
+        }
+        for &handle in &self.staged_view_handles {
+            srv_uav_pool.free_handle(handle);
+        }
+        for &handle in &self.staged_sampler_handles {
+            sampler_pool.free_handle(handle);
+        }
+    }
+}
+
 bitflags::bitflags! {
     struct TableTypes: u8 {
         const SRV_CBV_UAV = 0x1;
@@ -553,9 +636,9 @@ impl crate::Surface<Api> for Surface {
             resources,
             waitable,
             acquired_count: 0,
-            //format: config.format,
-            //size: config.extent,
-            //mode: config.present_mode,
+            present_mode: config.present_mode,
+            format: config.format,
+            size: config.extent,
         });
 
         Ok(())
@@ -576,7 +659,34 @@ impl crate::Surface<Api> for Surface {
         &mut self,
         timeout_ms: u32,
     ) -> Result<Option<crate::AcquiredSurfaceTexture<Api>>, crate::SurfaceError> {
-        Ok(None)
+        let swap_chain = self.swap_chain.as_mut().unwrap();
+
+        let should_present = swap_chain.wait(timeout_ms)?;
+        if !should_present {
+            return Ok(None);
+        }
+
+        let index = swap_chain.raw.GetCurrentBackBufferIndex();
+        let resource = swap_chain.resources[index as usize];
+        swap_chain.acquired_count += 1;
+
+        let texture = Texture {
+            resource,
+            format: swap_chain.format,
+            dimension: wgt::TextureDimension::D2,
+            size: swap_chain.size,
+            mip_level_count: 1,
+            sample_count: 1,
+            // swapchain back buffers are owned by the swapchain, not suballocated
+            // out of one of the device's memory pools
+            allocation: None,
+            // DXGI hands back buffers back in the `PRESENT` state
+            state: AtomicU32::new(d3d12::D3D12_RESOURCE_STATE_PRESENT),
+        };
+        Ok(Some(crate::AcquiredSurfaceTexture {
+            texture,
+            suboptimal: false,
+        }))
     }
     unsafe fn discard_texture(&mut self, texture: Texture) {}
 }
@@ -587,6 +697,19 @@ impl crate::Queue<Api> for Queue {
         command_buffers: &[&CommandBuffer],
         signal_fence: Option<(&mut Fence, crate::FenceValue)>,
     ) -> Result<(), crate::DeviceError> {
+        let command_lists = command_buffers
+            .iter()
+            .map(|cmd_buf| cmd_buf.raw.as_mut_ptr() as *mut d3d12::ID3D12CommandList)
+            .collect::<Vec<_>>();
+        self.raw
+            .ExecuteCommandLists(command_lists.len() as u32, command_lists.as_ptr());
+
+        if let Some((fence, value)) = signal_fence {
+            self.raw
+                .Signal(fence.raw, value)
+                .into_device_result("Signal")?;
+        }
+
         Ok(())
     }
     unsafe fn present(
@@ -594,6 +717,20 @@ impl crate::Queue<Api> for Queue {
         surface: &mut Surface,
         texture: Texture,
     ) -> Result<(), crate::SurfaceError> {
-        Ok(())
+        let sc = surface.swap_chain.as_mut().unwrap();
+        sc.acquired_count -= 1;
+
+        let (sync_interval, present_flags) = match sc.present_mode {
+            wgt::PresentMode::Immediate => (0, dxgi::DXGI_PRESENT_ALLOW_TEARING),
+            _ => (1, 0),
+        };
+
+        sc.raw
+            .Present(sync_interval, present_flags)
+            .into_result()
+            .map_err(|err| {
+                log::error!("Present failed: {}", err);
+                crate::SurfaceError::Lost
+            })
     }
 }